@@ -1,4 +1,8 @@
-use portable_pty::PtyPair;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{Context, Result};
+use portable_pty::{Child, PtyPair, PtySize};
 
 use crate::def_enum;
 
@@ -23,22 +27,223 @@ pub enum ChannelOpenFailureReason {
     SSH_OPEN_RESOURCE_SHORTAGE = 4,
 }
 
+/// The peer's advertised flow-control window (RFC 4254 § 5.2), shared
+/// between the `Channel` (updated from the main thread as
+/// `SSH_MSG_CHANNEL_WINDOW_ADJUST` packets arrive) and a channel's
+/// output-pump thread, which blocks in `reserve` instead of sending more
+/// `SSH_MSG_CHANNEL_DATA` than the peer said it can buffer.
+#[derive(Clone)]
+pub struct ChannelWindow {
+    state: Arc<(Mutex<u32>, Condvar)>,
+}
+
+impl ChannelWindow {
+    fn new(initial: u32) -> Self {
+        ChannelWindow {
+            state: Arc::new((Mutex::new(initial), Condvar::new())),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn adjust(&self, delta: u32) {
+        let (size, available) = &*self.state;
+        let mut size = size.lock().unwrap();
+        *size = size.saturating_add(delta);
+        available.notify_all();
+    }
+
+    pub fn consume(&self, amount: u32) {
+        let mut size = self.state.0.lock().unwrap();
+        *size = size.saturating_sub(amount);
+    }
+
+    /// Blocks until at least one byte of window is available, then
+    /// consumes and returns up to `want` bytes of it.
+    pub fn reserve(&self, want: u32) -> u32 {
+        let (size, available) = &*self.state;
+        let mut size = size.lock().unwrap();
+        while *size == 0 {
+            size = available.wait(size).unwrap();
+        }
+        let take = want.min(*size);
+        *size -= take;
+        take
+    }
+}
+
 pub struct Channel {
-    window_size: u32,
+    /// The peer's window for data *we* send *it*, seeded from the
+    /// `initial_window_size` it advertised in `SSH_MSG_CHANNEL_OPEN` (RFC
+    /// 4254 § 5.1): consumed by the output-pump thread (via
+    /// `window()`/`ChannelWindow::reserve`) and replenished by an incoming
+    /// `SSH_MSG_CHANNEL_WINDOW_ADJUST` (via `adjust_window`).
+    window: ChannelWindow,
+    /// Our own window for data the peer sends *us*, seeded from the window
+    /// size we advertised back in `SSH_MSG_CHANNEL_OPEN_CONFIRMATION`:
+    /// consumed as `SSH_MSG_CHANNEL_DATA` arrives (via `consume_window`).
+    /// Kept separate from `window` - they're independent per RFC 4254 §
+    /// 5.2, and sharing one counter would mean the client's own keystrokes
+    /// could stall our shell output.
+    received_window: u32,
+    /// What `received_window` gets topped back up to by
+    /// `replenish_received_window` - the window size we originally
+    /// advertised to the client.
+    received_window_initial: u32,
     max_packet_size: u32,
     pty_pair: Option<PtyPair>,
+    child: Option<Box<dyn Child + Send + Sync>>,
+    writer: Option<Box<dyn Write + Send>>,
 }
 
 impl Channel {
-    pub fn new(window_size: u32, max_packet_size: u32) -> Self {
+    /// `peer_window_size`/`peer_max_packet_size` are the values the peer
+    /// advertised in `SSH_MSG_CHANNEL_OPEN` (what *we* must respect when
+    /// sending it data); `received_window_size` is the window *we*
+    /// advertised back in `SSH_MSG_CHANNEL_OPEN_CONFIRMATION` (what the peer
+    /// must respect sending data to us).
+    pub fn new(peer_window_size: u32, peer_max_packet_size: u32, received_window_size: u32) -> Self {
         Channel {
-            window_size,
-            max_packet_size,
+            window: ChannelWindow::new(peer_window_size),
+            received_window: received_window_size,
+            received_window_initial: received_window_size,
+            max_packet_size: peer_max_packet_size,
             pty_pair: None,
+            child: None,
+            writer: None,
+        }
+    }
+
+    pub fn window_size(&self) -> u32 {
+        self.window.size()
+    }
+
+    pub fn max_packet_size(&self) -> u32 {
+        self.max_packet_size
+    }
+
+    /// Clones the handle a channel's output-pump thread blocks on to honor
+    /// the peer's advertised window (see `ChannelWindow::reserve`).
+    pub fn window(&self) -> ChannelWindow {
+        self.window.clone()
+    }
+
+    pub fn adjust_window(&mut self, delta: u32) {
+        self.window.adjust(delta);
+    }
+
+    pub fn consume_window(&mut self, amount: u32) {
+        self.received_window = self.received_window.saturating_sub(amount);
+    }
+
+    pub fn received_window(&self) -> u32 {
+        self.received_window
+    }
+
+    /// RFC 4254 § 5.2 - once our window for the peer's incoming data has
+    /// dropped to at most half of what we originally advertised, top it back
+    /// up to that and return the delta the caller should advertise in an
+    /// outgoing `SSH_MSG_CHANNEL_WINDOW_ADJUST`. Returns `None` if there's
+    /// nothing to replenish yet.
+    pub fn replenish_received_window(&mut self) -> Option<u32> {
+        if self.received_window > self.received_window_initial / 2 {
+            return None;
         }
+
+        let delta = self.received_window_initial - self.received_window;
+        self.received_window += delta;
+        Some(delta)
     }
 
     pub fn pty_pair(&self) -> &PtyPair {
         self.pty_pair.as_ref().expect("Pty not initialized yet")
     }
+
+    pub fn has_pty(&self) -> bool {
+        self.pty_pair.is_some()
+    }
+
+    // RFC 4254 § 6.2
+    pub fn open_pty(&mut self, size: PtySize) -> Result<()> {
+        let pty_system = portable_pty::native_pty_system();
+        let pair = pty_system
+            .openpty(size)
+            .context("Failed to allocate pty")?;
+        self.pty_pair = Some(pair);
+        Ok(())
+    }
+
+    /// Spawns `cmd` attached to this channel's pty slave, keeping the child
+    /// handle so the channel can later be torn down, and hands back a
+    /// reader for the pty master's output.
+    pub fn spawn(
+        &mut self,
+        cmd: portable_pty::CommandBuilder,
+    ) -> Result<Box<dyn std::io::Read + Send>> {
+        let pair = self.pty_pair();
+        let child = pair.slave.spawn_command(cmd).context("Failed to spawn child process")?;
+        self.child = Some(child);
+
+        self.writer = Some(
+            self.pty_pair()
+                .master
+                .take_writer()
+                .context("Failed to take pty master writer")?,
+        );
+
+        self.pty_pair()
+            .master
+            .try_clone_reader()
+            .context("Failed to clone pty master reader")
+    }
+
+    pub fn write_stdin(&mut self, data: &[u8]) -> Result<()> {
+        let writer = self
+            .writer
+            .as_mut()
+            .context("Channel has no process attached yet")?;
+        writer.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn is_child_alive(&mut self) -> bool {
+        match &mut self.child {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+
+    pub fn kill(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replenish_received_window_is_none_above_half() {
+        let mut channel = Channel::new(1, 1, 100);
+        channel.consume_window(40);
+        assert_eq!(channel.replenish_received_window(), None);
+        assert_eq!(channel.received_window(), 60);
+    }
+
+    #[test]
+    fn replenish_received_window_tops_back_up_at_half() {
+        let mut channel = Channel::new(1, 1, 100);
+        channel.consume_window(60);
+        assert_eq!(channel.received_window(), 40);
+
+        assert_eq!(channel.replenish_received_window(), Some(60));
+        assert_eq!(channel.received_window(), 100);
+
+        // Already topped up - nothing more to replenish.
+        assert_eq!(channel.replenish_received_window(), None);
+    }
 }
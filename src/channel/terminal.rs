@@ -0,0 +1,33 @@
+use crate::decoding::{u8_array_to_u32, PayloadReader};
+
+/// One `opcode`/`argument` pair from the RFC 4254 § 8 encoded
+/// terminal-modes string (e.g. `ECHO` or `ISIG` plus its value).
+pub struct TerminalMode {
+    pub opcode: u8,
+    pub argument: u32,
+}
+
+/// Decodes the `encoded terminal modes` string sent with `pty-req`: a
+/// sequence of `(opcode: byte, argument: uint32)` pairs terminated by the
+/// `TTY_OP_END` (0) opcode.
+pub fn decode_terminal_modes(encoded: &[u8]) -> Vec<TerminalMode> {
+    const TTY_OP_END: u8 = 0;
+
+    let mut reader = PayloadReader::new(encoded.to_vec());
+    let mut modes = Vec::new();
+
+    while let Some(opcode) = reader.next_byte() {
+        if opcode == TTY_OP_END {
+            break;
+        }
+
+        let argument_bytes = reader.next_n_bytes(4);
+        let Ok(argument) = u8_array_to_u32(&argument_bytes) else {
+            break;
+        };
+
+        modes.push(TerminalMode { opcode, argument });
+    }
+
+    modes
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+
+use crate::decoding::u8_array_to_u32;
+
+/// Holds the negotiated MAC digest for each direction so `PacketCodec`/the
+/// decrypting read path doesn't need to re-look the algorithm up on every
+/// packet.
+#[derive(Clone)]
+pub struct Crypto {
+    mac_algorithm_client_to_server: MessageDigest,
+    mac_algorithm_server_to_client: MessageDigest,
+}
+
+impl Crypto {
+    pub fn new(
+        mac_algorithm_client_to_server: MessageDigest,
+        mac_algorithm_server_to_client: MessageDigest,
+    ) -> Self {
+        Crypto {
+            mac_algorithm_client_to_server,
+            mac_algorithm_server_to_client,
+        }
+    }
+
+    // RFC 4253 § 6.4 - mac = HMAC(key, sequence_number || unencrypted_packet)
+    pub fn verify_mac(&self, sequence_number: u32, key: &[u8], data: &[u8], mac: &[u8]) -> Result<bool> {
+        let computed = self.compute_mac(
+            self.mac_algorithm_client_to_server,
+            sequence_number,
+            key,
+            data,
+        )?;
+        Ok(computed == mac)
+    }
+
+    pub fn compute_mac_server_to_client(
+        &self,
+        sequence_number: u32,
+        key: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.compute_mac(
+            self.mac_algorithm_server_to_client,
+            sequence_number,
+            key,
+            data,
+        )
+    }
+
+    fn compute_mac(
+        &self,
+        digest: MessageDigest,
+        sequence_number: u32,
+        key: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let pkey = PKey::hmac(key)?;
+        let mut signer = Signer::new(digest, &pkey)?;
+        signer.update(&sequence_number.to_be_bytes())?;
+        signer.update(data)?;
+        Ok(signer.sign_to_vec()?)
+    }
+}
+
+/// Reads a single RFC 4251 § 5 `string` field from the front of `data`,
+/// returning it and the remaining bytes. Used for the SSH public-key and
+/// signature wire formats (RFC 4253 § 6.6), which are themselves just
+/// sequences of `string`/`mpint` fields independent of packet framing.
+fn read_field(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    if data.len() < 4 {
+        return Err(anyhow!("Field too short to contain a length"));
+    }
+    let length = u8_array_to_u32(&data[0..4])? as usize;
+    let rest = &data[4..];
+    if rest.len() < length {
+        return Err(anyhow!("Field declares {} bytes but only {} remain", length, rest.len()));
+    }
+    Ok((&rest[..length], &rest[length..]))
+}
+
+// RFC 4253 § 6.6 - verifies a `publickey` userauth signature over
+// `signed_data` (session_id || request-up-to-the-public-key-blob).
+pub fn verify_signature(
+    public_key_algorithm: &str,
+    public_key_blob: &[u8],
+    signature_blob: &[u8],
+    signed_data: &[u8],
+) -> Result<bool> {
+    let (_sig_algorithm, sig_rest) = read_field(signature_blob)?;
+    let (signature, _) = read_field(sig_rest)?;
+
+    let pkey = match public_key_algorithm {
+        "ssh-ed25519" => {
+            let (_name, rest) = read_field(public_key_blob)?;
+            let (key_bytes, _) = read_field(rest)?;
+            PKey::public_key_from_raw_bytes(key_bytes, Id::ED25519)?
+        }
+        "ssh-rsa" => {
+            let (_name, rest) = read_field(public_key_blob)?;
+            let (e, rest) = read_field(rest)?;
+            let (n, _) = read_field(rest)?;
+            let rsa = Rsa::from_public_components(
+                openssl::bn::BigNum::from_slice(n)?,
+                openssl::bn::BigNum::from_slice(e)?,
+            )?;
+            PKey::from_rsa(rsa)?
+        }
+        other => return Err(anyhow!("Unsupported public key algorithm '{}'", other)),
+    };
+
+    let mut verifier = if public_key_algorithm == "ssh-ed25519" {
+        Verifier::new_without_digest(&pkey)?
+    } else {
+        Verifier::new(MessageDigest::sha1(), &pkey)?
+    };
+    verifier.update(signed_data)?;
+    Ok(verifier.verify(signature)?)
+}
@@ -1,17 +1,14 @@
-use std::{
-    io::{BufReader, Read},
-    net::TcpStream,
-};
+use std::io::Read;
 
 use anyhow::{anyhow, Context, Result};
 use log::{log_enabled, trace, Level};
 use num_traits::FromPrimitive;
-use openssl::symm::{Crypter, Mode};
 
 use crate::{
-    encoding::{encode_string, PACKET_LENGTH_SIZE, STRING_LENGTH_SIZE},
+    codec::PacketTooLarge,
+    encoding::{PACKET_LENGTH_SIZE, STRING_LENGTH_SIZE},
     session::Session,
-    types::MessageType,
+    types::{DisconnectReason, MessageType},
 };
 
 pub struct PayloadReader {
@@ -72,11 +69,40 @@ impl PayloadReader {
         let bytes = self.iter.by_ref().take(n).collect();
         bytes
     }
+
+    /// Reads a raw (not length-prefixed) big-endian `uint32` field, e.g. a
+    /// channel number. Unlike `next_n_bytes(4).try_into().unwrap()`, fails
+    /// cleanly instead of panicking when the payload is shorter than
+    /// declared.
+    pub fn next_u32(&mut self) -> Result<u32> {
+        let bytes = self.next_n_bytes(4);
+        u8_array_to_u32(&bytes)
+    }
+}
+
+/// RFC 4253 § 6 - a crafted `padding_length` larger than `packet_length`
+/// would otherwise underflow the payload length computed in `get_payload`.
+#[derive(Debug)]
+pub struct InvalidPadding {
+    pub padding_length: u8,
+    pub packet_length: u32,
+}
+
+impl std::fmt::Display for InvalidPadding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "padding_length of {} is invalid for packet_length of {}",
+            self.padding_length, self.packet_length
+        )
+    }
 }
 
+impl std::error::Error for InvalidPadding {}
+
 #[derive(Debug)]
 pub struct DecodedPacket {
-    payload: Vec<u8>,
+    pub(crate) payload: Vec<u8>,
 }
 
 impl DecodedPacket {
@@ -101,134 +127,96 @@ impl DecodedPacket {
 }
 
 // RFC 4253 § 6
-pub fn decode_packet(session: &Session) -> Result<DecodedPacket> {
+pub fn decode_packet(session: &mut Session) -> Result<DecodedPacket> {
+    let encrypted = session.codec().is_encrypted();
     trace!(
         "-- BEGIN PACKET DECODING{} --",
-        if session.kex().finished {
-            " (ENCRYPTED)"
-        } else {
-            ""
-        }
+        if encrypted { " (ENCRYPTED)" } else { "" }
     );
 
-    let decoded_packet = if session.kex().finished {
-        decode_packet_encrypted(session)?
-    } else {
-        decode_packet_unencrypted(session.stream())?
+    // The codec may already be holding a full packet buffered from a
+    // previous read that contained more than one packet, so a packet is
+    // decoded out of what's already there before blocking on the socket.
+    let mut buf = [0u8; 4096];
+    let packet = match decode_buffered(session, &[])? {
+        Some(packet) => packet,
+        None => loop {
+            let n = session
+                .stream_mut()
+                .read(&mut buf)
+                .context("Failed reading from stream")?;
+            if n == 0 {
+                return Err(anyhow!("Connection closed by peer"));
+            }
+            if let Some(packet) = decode_buffered(session, &buf[..n])? {
+                break packet;
+            }
+        },
     };
 
     trace!(
         "-- END PACKET DECODING{} --",
-        if session.kex().finished {
-            " (ENCRYPTED)"
-        } else {
-            ""
-        }
+        if encrypted { " (ENCRYPTED)" } else { "" }
     );
-    Ok(decoded_packet)
+    Ok(packet)
 }
-fn decode_packet_encrypted(session: &Session) -> Result<DecodedPacket> {
-    let block_size = session
-        .algorithms()
-        .as_ref()
-        .unwrap()
-        .encryption_algorithms_client_to_server
-        .details
-        .block_size;
-
-    let cipher = session
-        .algorithms()
-        .as_ref()
-        .unwrap()
-        .encryption_algorithms_client_to_server
-        .details
-        .cipher;
-    let mut decrypter = Crypter::new(
-        cipher,
-        Mode::Decrypt,
-        session.enc_key_client_server(),
-        Some(session.iv_client_server()),
-    )?;
-    decrypter.pad(false);
-
-    // Read first block
-    let mut reader = BufReader::new(session.stream());
-    let mut first_block = vec![0u8; block_size];
-    reader.read_exact(&mut first_block)?;
-
-    // Decrypt first block to get packet length
-    let mut first_block_dec = vec![0u8; block_size];
-    decrypter.update(&first_block, &mut first_block_dec)?;
-
-    let packet_length_bytes = &first_block_dec[0..PACKET_LENGTH_SIZE];
-    let packet_length = u8_array_to_u32(packet_length_bytes)?;
-    trace!("packet_length = {}", packet_length);
-
-    // Read rest of encrypted packet
-    let mut rest_enc = vec![0u8; packet_length as usize - (block_size - PACKET_LENGTH_SIZE)];
-    reader.read_exact(&mut rest_enc)?;
-
-    // Decrypt rest of encrypted packet
-    let mut rest_dec = vec![0u8; rest_enc.len()];
-    decrypter.update(&rest_enc, &mut rest_dec)?;
-
-    // Join first block and rest of decrypted packet
-    let mut packet_dec = first_block_dec[PACKET_LENGTH_SIZE..].to_vec();
-    packet_dec.extend(rest_dec);
-
-    let mac_len = session
-        .algorithms()
-        .as_ref()
-        .unwrap()
-        .mac_algorithms_client_to_server
-        .details
-        .hash
-        .size();
-    let mut mac = vec![0u8; mac_len];
-    reader.read_exact(&mut mac)?;
-
-    let valid = session.crypto().as_ref().unwrap().verify_mac(
-        session.sequence_number(),
-        session.integrity_key_client_server(),
-        // For some reason, this has to be encoded as string
-        &encode_string(&packet_dec),
-        &mac,
-    )?;
-    if !valid {
-        return Err(anyhow!("MAC verification failed"));
-    }
-
-    trace!("packet = {:02x?}", packet_dec);
 
-    let payload = get_payload(packet_dec, packet_length)?;
-    Ok(DecodedPacket { payload })
-}
-fn decode_packet_unencrypted(stream: &TcpStream) -> Result<DecodedPacket> {
-    let mut reader = BufReader::new(stream);
-
-    let mut packet_length_bytes = [0u8; PACKET_LENGTH_SIZE];
-    reader
-        .read_exact(&mut packet_length_bytes)
-        .context("Failed reading packet_length")?;
-    let packet_length = u8_array_to_u32(&packet_length_bytes)?;
-    trace!("packet_length = {} bytes", packet_length);
-
-    let mut packet = vec![0u8; packet_length as usize];
-    reader
-        .read_exact(&mut packet)
-        .context("Failed reading packet")?;
-
-    let payload = get_payload(packet, packet_length)?;
-    Ok(DecodedPacket { payload })
+fn decode_buffered(session: &mut Session, bytes: &[u8]) -> Result<Option<DecodedPacket>> {
+    // Bound to a variable (rather than matched directly) so the codec's
+    // mutex guard is dropped before `disconnect` below needs the lock back
+    // to encode the disconnect packet - matching on the lock call directly
+    // would keep the guard alive for the whole match and deadlock.
+    let decoded = session.codec().decode(bytes);
+    match decoded {
+        Ok(packet) => Ok(packet),
+        // RFC 4253 § 6.1 - a malicious `packet_length` would otherwise force
+        // an allocation of up to 4 GiB before we ever look at the data.
+        Err(err)
+            if err.downcast_ref::<PacketTooLarge>().is_some()
+                || err.downcast_ref::<InvalidPadding>().is_some() =>
+        {
+            session.disconnect(DisconnectReason::SSH_DISCONNECT_PROTOCOL_ERROR)?;
+            Err(err)
+        }
+        Err(err) => Err(err),
+    }
 }
+
 /// `packet` must not contain the packet_length field
-fn get_payload(packet: Vec<u8>, packet_length: u32) -> Result<Vec<u8>> {
+pub(crate) fn get_payload(packet: Vec<u8>, packet_length: u32) -> Result<Vec<u8>> {
     let mut reader = packet.into_iter();
     let reader = reader.by_ref();
 
-    let padding_length = *reader.take(1).collect::<Vec<u8>>().first().unwrap();
+    // RFC 4253 § 6 - `packet_length` is attacker-controlled; a declared
+    // length of 0 leaves no byte in `packet` for `padding_length` itself, so
+    // this has to be checked before reading it, not folded into the
+    // `padding_length > packet_length - 1` check below.
+    if packet_length == 0 {
+        return Err(InvalidPadding {
+            padding_length: 0,
+            packet_length,
+        }
+        .into());
+    }
+
+    let padding_length = *reader
+        .take(1)
+        .collect::<Vec<u8>>()
+        .first()
+        .ok_or_else(|| anyhow!("Packet is shorter than its declared packet_length"))?;
     trace!("padding_length = {} bytes", padding_length);
 
+    // RFC 4253 § 6 - `padding_length` is attacker-controlled; without this
+    // check a crafted combination with `packet_length` underflows `n1`
+    // (panics in debug, wraps to a huge `take` count in release).
+    if padding_length as u32 > packet_length - 1 {
+        return Err(InvalidPadding {
+            padding_length,
+            packet_length,
+        }
+        .into());
+    }
+
     let n1 = packet_length - (padding_length as u32) - 1;
     let payload = reader.take(n1 as usize).collect::<Vec<u8>>();
 
@@ -280,3 +268,35 @@ pub fn packet_too_short<T>(var_name: &str) -> Result<T> {
         var_name
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A client sending the 4 bytes `00 00 00 00` right after the ident
+    // exchange reaches this with an empty `packet` and `packet_length == 0`
+    // - regression test for a panic in reading `padding_length` before this
+    // case was checked.
+    #[test]
+    fn get_payload_rejects_zero_packet_length_instead_of_panicking() {
+        let err = get_payload(Vec::new(), 0).unwrap_err();
+        assert!(err.downcast_ref::<InvalidPadding>().is_some());
+    }
+
+    #[test]
+    fn get_payload_rejects_padding_length_that_would_underflow_n1() {
+        // packet_length = 1 leaves no room for payload or padding beyond the
+        // padding_length byte itself, so any non-zero padding_length here
+        // would underflow `n1`.
+        let err = get_payload(vec![5], 1).unwrap_err();
+        assert!(err.downcast_ref::<InvalidPadding>().is_some());
+    }
+
+    #[test]
+    fn get_payload_returns_payload_without_padding() {
+        // padding_length = 4, 3-byte payload "abc", 4 bytes of padding.
+        let packet = vec![4, b'a', b'b', b'c', 0, 0, 0, 0];
+        let payload = get_payload(packet, 8).unwrap();
+        assert_eq!(payload, b"abc");
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+
+use crate::types::MessageType;
+
+pub const PACKET_LENGTH_SIZE: usize = 4;
+pub const STRING_LENGTH_SIZE: usize = 4;
+/// RFC 4253 § 6 - payload must be padded so `packet_length` is a multiple of
+/// the cipher block size (or 8, whichever is larger), with at least 4 bytes
+/// of padding.
+const MIN_PADDING: usize = 4;
+const BLOCK_SIZE_UNENCRYPTED: usize = 8;
+
+// RFC 4251 § 5
+pub fn encode_string(value: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(STRING_LENGTH_SIZE + value.len());
+    encoded.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+/// Builds an RFC 4253 § 6 binary packet one field at a time.
+pub struct PacketBuilder {
+    payload: Vec<u8>,
+}
+
+impl PacketBuilder {
+    pub fn new(message_type: MessageType) -> Self {
+        PacketBuilder {
+            payload: vec![message_type as u8],
+        }
+    }
+
+    pub fn write_byte(mut self, value: u8) -> Self {
+        self.payload.push(value);
+        self
+    }
+
+    pub fn write_bool(self, value: bool) -> Self {
+        self.write_byte(value as u8)
+    }
+
+    pub fn write_u32(mut self, value: u32) -> Self {
+        self.payload.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// Writes a length-prefixed `string` (RFC 4251 § 5), used for both
+    /// opaque byte strings and ASCII name fields.
+    pub fn write_bytes(mut self, value: &[u8]) -> Self {
+        self.payload.extend_from_slice(&encode_string(value));
+        self
+    }
+
+    pub fn write_name_list(self, names: &[&str]) -> Self {
+        self.write_bytes(names.join(",").as_bytes())
+    }
+
+    /// The message type byte followed by the fields written so far, before
+    /// padding is added. Used to capture a KEXINIT payload for the exchange
+    /// hash (RFC 4253 § 8) without having to re-parse the built packet.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Assembles the final on-the-wire packet: `packet_length`,
+    /// `padding_length`, the payload collected so far and random padding.
+    pub fn build(self) -> Result<Vec<u8>> {
+        let padded_len = 1 /* padding_length */ + self.payload.len() + MIN_PADDING;
+        let padding_length = if padded_len % BLOCK_SIZE_UNENCRYPTED == 0 {
+            MIN_PADDING
+        } else {
+            MIN_PADDING + (BLOCK_SIZE_UNENCRYPTED - padded_len % BLOCK_SIZE_UNENCRYPTED)
+        };
+
+        let packet_length = 1 + self.payload.len() + padding_length;
+
+        let mut packet = Vec::with_capacity(PACKET_LENGTH_SIZE + packet_length);
+        packet.extend_from_slice(&(packet_length as u32).to_be_bytes());
+        packet.push(padding_length as u8);
+        packet.extend_from_slice(&self.payload);
+        packet.extend(std::iter::repeat(0u8).take(padding_length));
+
+        Ok(packet)
+    }
+}
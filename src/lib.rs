@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use openssl::pkey::{PKey, Private};
+
+pub mod channel;
+pub mod codec;
+pub mod crypto;
+pub mod decoding;
+pub mod encoding;
+pub mod session;
+pub mod types;
+
+/// Generates a C-like enum together with a `.as_str()` accessor mapping each
+/// variant to its wire name, so algorithm/request names aren't repeated as
+/// free-floating string literals at every call site.
+#[macro_export]
+macro_rules! def_enum {
+    (pub $name:ident => $ty:ty { $($variant:ident => $value:expr),* $(,)? }) => {
+        #[allow(non_camel_case_types, dead_code)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> $ty {
+                match self {
+                    $(Self::$variant => $value),*
+                }
+            }
+
+            pub fn from_str(value: &str) -> Option<Self> {
+                match value {
+                    $($value => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+/// A public key allowed to authenticate a given user, in the style of an
+/// `authorized_keys` entry (algorithm name + base64-decoded blob).
+#[derive(Clone)]
+pub struct AuthorizedKey {
+    pub algorithm: String,
+    pub blob: Vec<u8>,
+}
+
+/// One entry of the server's user database: the username and the
+/// credentials accepted for it.
+#[derive(Clone, Default)]
+pub struct AuthorizedUser {
+    pub username: String,
+    pub password: Option<String>,
+    pub authorized_keys: Vec<AuthorizedKey>,
+}
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub ident_string: String,
+    pub authorized_users: Vec<AuthorizedUser>,
+    /// The server's own identity, used to sign the key exchange hash.
+    pub host_key: PKey<Private>,
+
+    /// RFC 4253 § 6.1 - servers and clients may limit the size of packets
+    /// they are willing to accept; we default to the RFC's minimum
+    /// guaranteed-supported size of 35000 bytes.
+    pub max_packet_length: u32,
+    /// How long to wait for activity on the socket before giving up on a
+    /// stalled client.
+    pub read_timeout: Duration,
+}
+
+impl ServerConfig {
+    pub fn new(host_key: PKey<Private>) -> Self {
+        ServerConfig {
+            ident_string: "SSH-2.0-mini-sshd".to_string(),
+            authorized_users: Vec::new(),
+            host_key,
+            max_packet_length: 35000,
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+}
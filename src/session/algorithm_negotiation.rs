@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use log::debug;
+use openssl::hash::MessageDigest;
+use openssl::symm::Cipher;
+
+use crate::{
+    decoding::{DecodedPacket, PayloadReader},
+    encoding::PacketBuilder,
+    session::Session,
+    types::MessageType,
+};
+
+pub const KEX_ALGORITHMS: &[&str] = &[
+    "curve25519-sha256",
+    "diffie-hellman-group14-sha256",
+    "diffie-hellman-group14-sha1",
+];
+const SERVER_HOST_KEY_ALGORITHMS: &[&str] = &["ssh-ed25519", "ssh-rsa"];
+const ENCRYPTION_ALGORITHMS: &[&str] = &["aes128-ctr"];
+const MAC_ALGORITHMS: &[&str] = &["hmac-sha2-256"];
+const COMPRESSION_ALGORITHMS: &[&str] = &["none"];
+
+#[derive(Clone, Copy)]
+pub struct EncryptionAlgorithmDetails {
+    pub cipher: Cipher,
+    pub block_size: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct MacAlgorithmDetails {
+    pub hash: MessageDigest,
+}
+
+#[derive(Clone)]
+pub struct NegotiatedAlgorithm<D> {
+    pub name: String,
+    pub details: D,
+}
+
+#[derive(Clone)]
+pub struct Algorithms {
+    pub kex_algorithm: String,
+    pub server_host_key_algorithm: String,
+    pub encryption_algorithms_client_to_server: NegotiatedAlgorithm<EncryptionAlgorithmDetails>,
+    pub encryption_algorithms_server_to_client: NegotiatedAlgorithm<EncryptionAlgorithmDetails>,
+    pub mac_algorithms_client_to_server: NegotiatedAlgorithm<MacAlgorithmDetails>,
+    pub mac_algorithms_server_to_client: NegotiatedAlgorithm<MacAlgorithmDetails>,
+}
+
+impl Default for Algorithms {
+    fn default() -> Self {
+        let encryption = NegotiatedAlgorithm {
+            name: "aes128-ctr".to_string(),
+            details: EncryptionAlgorithmDetails {
+                cipher: Cipher::aes_128_ctr(),
+                block_size: 16,
+            },
+        };
+        let mac = NegotiatedAlgorithm {
+            name: "hmac-sha2-256".to_string(),
+            details: MacAlgorithmDetails {
+                hash: MessageDigest::sha256(),
+            },
+        };
+
+        Algorithms {
+            kex_algorithm: String::new(),
+            server_host_key_algorithm: String::new(),
+            encryption_algorithms_client_to_server: encryption.clone(),
+            encryption_algorithms_server_to_client: encryption,
+            mac_algorithms_client_to_server: mac.clone(),
+            mac_algorithms_server_to_client: mac,
+        }
+    }
+}
+
+fn pick<'a>(client: &[String], server: &'a [&'a str]) -> Result<&'a str> {
+    server
+        .iter()
+        .find(|candidate| client.iter().any(|c| c == *candidate))
+        .copied()
+        .context("Could not agree on an algorithm with the client")
+}
+
+impl Session {
+    // RFC 4253 § 7.1. Also re-entered for a rekey (RFC 4253 § 9): either
+    // side's `SSH_MSG_KEXINIT` is handled the same way regardless of
+    // whether it's the session's first exchange or a later one.
+    pub(crate) fn algorithm_negotiation(
+        &mut self,
+        packet: &DecodedPacket,
+        reader: &mut PayloadReader,
+    ) -> Result<()> {
+        debug!("--- BEGIN ALGORITHM NEGOTIATION ---");
+
+        self.client_kexinit_payload = packet.payload_with_msg_type().clone();
+        reader.next_n_bytes(16); // cookie
+
+        let client_kex_algorithms = reader.next_name_list()?;
+        let client_server_host_key_algorithms = reader.next_name_list()?;
+        let client_enc_c2s = reader.next_name_list()?;
+        let _client_enc_s2c = reader.next_name_list()?;
+        let client_mac_c2s = reader.next_name_list()?;
+        let _client_mac_s2c = reader.next_name_list()?;
+
+        let kex_algorithm = pick(&client_kex_algorithms, KEX_ALGORITHMS)?.to_string();
+        let server_host_key_algorithm =
+            pick(&client_server_host_key_algorithms, SERVER_HOST_KEY_ALGORITHMS)?.to_string();
+        pick(&client_enc_c2s, ENCRYPTION_ALGORITHMS)?;
+        pick(&client_mac_c2s, MAC_ALGORITHMS)?;
+
+        debug!("Negotiated kex_algorithm = {}", kex_algorithm);
+
+        let algorithms = self.algorithms.get_or_insert_with(Algorithms::default);
+        algorithms.kex_algorithm = kex_algorithm;
+        algorithms.server_host_key_algorithm = server_host_key_algorithm;
+
+        // If we already sent our own SSH_MSG_KEXINIT for this round (we
+        // proactively initiated a rekey, see `maybe_initiate_rekey`), the
+        // client's SSH_MSG_KEXINIT just crossed ours on the wire and
+        // doesn't need a reply - each side only sends one per round.
+        if !self.kex.in_progress {
+            self.send_kexinit()?;
+        }
+        self.kex.in_progress = true;
+
+        debug!("--- END ALGORITHM NEGOTIATION ---");
+        Ok(())
+    }
+
+    /// Sends our own `SSH_MSG_KEXINIT`, advertising the algorithms this
+    /// server supports. Sent once per kex round, either as the reply to the
+    /// peer's `SSH_MSG_KEXINIT` or, for a server-initiated rekey, before the
+    /// peer has sent theirs.
+    pub(crate) fn send_kexinit(&mut self) -> Result<()> {
+        let mut packet = PacketBuilder::new(MessageType::SSH_MSG_KEXINIT);
+        // The cookie is 16 raw bytes, not a length-prefixed `string`.
+        for _ in 0..16 {
+            packet = packet.write_byte(0);
+        }
+        let packet = packet
+            .write_name_list(KEX_ALGORITHMS)
+            .write_name_list(SERVER_HOST_KEY_ALGORITHMS)
+            .write_name_list(ENCRYPTION_ALGORITHMS)
+            .write_name_list(ENCRYPTION_ALGORITHMS)
+            .write_name_list(MAC_ALGORITHMS)
+            .write_name_list(MAC_ALGORITHMS)
+            .write_name_list(COMPRESSION_ALGORITHMS)
+            .write_name_list(COMPRESSION_ALGORITHMS)
+            .write_name_list(&[])
+            .write_name_list(&[])
+            .write_bool(false)
+            .write_u32(0);
+        self.server_kexinit_payload = packet.payload().to_vec();
+        self.send_packet(packet)
+    }
+}
@@ -0,0 +1,281 @@
+use std::io::Read;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use portable_pty::{CommandBuilder, PtySize};
+
+use crate::{
+    channel::{terminal::decode_terminal_modes, Channel, ChannelRequestType, ChannelWindow, SESSION_REQUEST},
+    codec::PacketCodec,
+    decoding::{u8_to_bool, PayloadReader},
+    encoding::PacketBuilder,
+    session::Session,
+    types::MessageType,
+};
+
+/// Window size/max packet size we advertise to the client in
+/// `SSH_MSG_CHANNEL_OPEN_CONFIRMATION` (RFC 4254 § 5.1) - i.e. what the
+/// client must respect sending data to *us* (see `Channel::received_window`).
+/// What we must respect sending data to the client comes from its own
+/// advertised values instead (see `channel_open`).
+const WINDOW_SIZE: u32 = 1024 * 1024;
+const MAX_PACKET_SIZE: u32 = 32 * 1024;
+
+impl Session {
+    // RFC 4254 § 5.1
+    pub(crate) fn channel_open(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let channel_type = String::from_utf8(reader.next_string()?)?;
+        let sender_channel = reader.next_u32()?;
+        let peer_window_size = reader.next_u32()?;
+        // A max_packet_size of 0 would make ChannelSink::send loop forever
+        // trying to carve a zero-sized chunk out of non-empty data.
+        let peer_max_packet_size = reader.next_u32()?.max(1);
+
+        if channel_type != SESSION_REQUEST {
+            let packet = PacketBuilder::new(MessageType::SSH_MSG_CHANNEL_OPEN_FAILURE)
+                .write_u32(sender_channel)
+                .write_u32(crate::channel::ChannelOpenFailureReason::SSH_OPEN_UNKNOWN_CHANNEL_TYPE as u32)
+                .write_bytes(b"")
+                .write_bytes(b"");
+            return self.send_packet(packet);
+        }
+
+        let local_channel = self.next_channel_id;
+        self.next_channel_id += 1;
+        self.channels.insert(
+            local_channel,
+            Channel::new(peer_window_size, peer_max_packet_size, WINDOW_SIZE),
+        );
+
+        debug!("Opened channel {} (peer channel {})", local_channel, sender_channel);
+        self.channel_peer_ids.insert(local_channel, sender_channel);
+
+        let packet = PacketBuilder::new(MessageType::SSH_MSG_CHANNEL_OPEN_CONFIRMATION)
+            .write_u32(sender_channel)
+            .write_u32(local_channel)
+            .write_u32(WINDOW_SIZE)
+            .write_u32(MAX_PACKET_SIZE);
+        self.send_packet(packet)
+    }
+
+    // RFC 4254 § 4 / § 6
+    pub(crate) fn channel_request(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let local_channel = reader.next_u32()?;
+        let request_type = String::from_utf8(reader.next_string()?)?;
+        let want_reply = u8_to_bool(
+            reader
+                .next_byte()
+                .ok_or_else(|| anyhow!("Packet too short - 'want_reply' could not be read"))?,
+        )?;
+
+        let result = if request_type == ChannelRequestType::PTY_REQ.as_str() {
+            self.handle_pty_req(local_channel, reader)
+        } else if request_type == ChannelRequestType::ENV.as_str() {
+            // RFC 4254 § 6.4 - environment variables are best-effort; we
+            // don't forward them to the spawned shell.
+            let _name = reader.next_string()?;
+            let _value = reader.next_string()?;
+            Ok(())
+        } else if request_type == ChannelRequestType::SHELL.as_str() {
+            self.handle_shell(local_channel, None)
+        } else if request_type == ChannelRequestType::EXEC.as_str() {
+            let command = String::from_utf8(reader.next_string()?)?;
+            self.handle_shell(local_channel, Some(command))
+        } else {
+            Err(anyhow!("Unsupported channel request type '{}'", request_type))
+        };
+
+        if want_reply {
+            let msg_type = if result.is_ok() {
+                MessageType::SSH_MSG_CHANNEL_SUCCESS
+            } else {
+                MessageType::SSH_MSG_CHANNEL_FAILURE
+            };
+            let packet = PacketBuilder::new(msg_type).write_u32(self.peer_channel(local_channel)?);
+            self.send_packet(packet)?;
+        }
+
+        result
+    }
+
+    fn handle_pty_req(&mut self, local_channel: u32, reader: &mut PayloadReader) -> Result<()> {
+        let term = String::from_utf8(reader.next_string()?)?;
+        let width_chars = reader.next_u32()?;
+        let height_rows = reader.next_u32()?;
+        let width_px = reader.next_u32()?;
+        let height_px = reader.next_u32()?;
+        let terminal_modes = reader.next_string()?;
+        let modes = decode_terminal_modes(&terminal_modes);
+        debug!(
+            "pty-req: term = {:?}, {}x{}, {} terminal modes",
+            term,
+            width_chars,
+            height_rows,
+            modes.len()
+        );
+
+        let channel = self
+            .channels
+            .get_mut(&local_channel)
+            .ok_or_else(|| anyhow!("Unknown channel {}", local_channel))?;
+        channel.open_pty(PtySize {
+            rows: height_rows as u16,
+            cols: width_chars as u16,
+            pixel_width: width_px as u16,
+            pixel_height: height_px as u16,
+        })
+    }
+
+    fn handle_shell(&mut self, local_channel: u32, command: Option<String>) -> Result<()> {
+        let peer_channel = self.peer_channel(local_channel)?;
+        // Grabbed before the mutable borrow of `self.channels` below, since
+        // `codec_handle`/`writer_sender` borrow all of `self`.
+        let codec = self.codec_handle();
+        let writer_tx = self.writer_sender();
+
+        let channel = self
+            .channels
+            .get_mut(&local_channel)
+            .ok_or_else(|| anyhow!("Unknown channel {}", local_channel))?;
+        if !channel.has_pty() {
+            return Err(anyhow!("Channel {} has no pty allocated", local_channel));
+        }
+
+        let mut cmd = match &command {
+            Some(command) => {
+                let mut cmd = CommandBuilder::new("sh");
+                cmd.args(["-c", command]);
+                cmd
+            }
+            None => CommandBuilder::new("sh"),
+        };
+        cmd.env("TERM", "xterm");
+
+        let mut reader = channel.spawn(cmd)?;
+
+        let sink = ChannelSink {
+            peer_channel,
+            max_packet_size: channel.max_packet_size(),
+            window: channel.window(),
+            codec,
+            writer_tx,
+        };
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if sink.send(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    // RFC 4254 § 5.2
+    pub(crate) fn channel_data(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let local_channel = reader.next_u32()?;
+        let data = reader.next_string()?;
+        let peer_channel = self.peer_channel(local_channel)?;
+
+        let channel = self
+            .channels
+            .get_mut(&local_channel)
+            .ok_or_else(|| anyhow!("Unknown channel {}", local_channel))?;
+        channel.consume_window(data.len() as u32);
+        channel.write_stdin(&data)?;
+        let replenish = channel.replenish_received_window();
+
+        // RFC 4254 § 5.2 - tell the peer its window for sending us data has
+        // room again, or a transfer bigger than the initial window (e.g. a
+        // large `cat > file` or paste) would stall forever once it hit zero.
+        if let Some(delta) = replenish {
+            let packet = PacketBuilder::new(MessageType::SSH_MSG_CHANNEL_WINDOW_ADJUST)
+                .write_u32(peer_channel)
+                .write_u32(delta);
+            self.send_packet(packet)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn channel_window_adjust(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let local_channel = reader.next_u32()?;
+        let bytes_to_add = reader.next_u32()?;
+
+        let channel = self
+            .channels
+            .get_mut(&local_channel)
+            .ok_or_else(|| anyhow!("Unknown channel {}", local_channel))?;
+        channel.adjust_window(bytes_to_add);
+        Ok(())
+    }
+
+    pub(crate) fn channel_eof(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let local_channel = reader.next_u32()?;
+        debug!("Received EOF on channel {}", local_channel);
+        Ok(())
+    }
+
+    pub(crate) fn channel_close(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let local_channel = reader.next_u32()?;
+        let peer_channel = self.peer_channel(local_channel)?;
+
+        if let Some(mut channel) = self.channels.remove(&local_channel) {
+            channel.kill();
+        }
+        self.channel_peer_ids.remove(&local_channel);
+
+        let packet = PacketBuilder::new(MessageType::SSH_MSG_CHANNEL_CLOSE).write_u32(peer_channel);
+        self.send_packet(packet)
+    }
+
+    fn peer_channel(&self, local_channel: u32) -> Result<u32> {
+        self.channel_peer_ids
+            .get(&local_channel)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown channel {}", local_channel))
+    }
+}
+
+/// Lets a channel's output-pump thread send `SSH_MSG_CHANNEL_DATA` packets
+/// on its own, through the same codec (so sequence numbers/encryption stay
+/// correct) and the same writer thread (so it's never a second writer on
+/// the socket) as everything else - without needing a `&mut Session`.
+struct ChannelSink {
+    peer_channel: u32,
+    max_packet_size: u32,
+    window: ChannelWindow,
+    codec: Arc<Mutex<PacketCodec>>,
+    writer_tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl ChannelSink {
+    /// Splits `data` into chunks of at most `max_packet_size`, blocking
+    /// before each one until the peer's advertised window (RFC 4254 § 5.2)
+    /// has room for it.
+    fn send(&self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let want = (data.len() as u32).min(self.max_packet_size);
+            let take = self.window.reserve(want) as usize;
+            let (chunk, rest) = data.split_at(take);
+            data = rest;
+
+            let packet = PacketBuilder::new(MessageType::SSH_MSG_CHANNEL_DATA)
+                .write_u32(self.peer_channel)
+                .write_bytes(chunk);
+            let encoded = self.codec.lock().unwrap().encode(packet)?;
+            self.writer_tx
+                .send(encoded)
+                .map_err(|_| anyhow!("Writer thread has shut down"))?;
+        }
+        Ok(())
+    }
+}
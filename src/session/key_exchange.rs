@@ -0,0 +1,418 @@
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::derive::Deriver;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+
+use crate::{
+    crypto::Crypto,
+    decoding::PayloadReader,
+    encoding::{encode_string, PacketBuilder},
+    session::Session,
+    types::MessageType,
+};
+
+/// Key material for both directions derived from a completed key exchange
+/// (RFC 4253 § 7.2). Handed to `PacketCodec::stage_keys`, which turns it
+/// into the per-direction state that the codec keeps as its pending and
+/// then current keys.
+#[derive(Clone, Default)]
+pub struct KeySet {
+    pub enc_key_client_server: Vec<u8>,
+    pub enc_key_server_client: Vec<u8>,
+    pub iv_client_server: Vec<u8>,
+    pub iv_server_client: Vec<u8>,
+    pub integrity_key_client_server: Vec<u8>,
+    pub integrity_key_server_client: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct KexState {
+    /// Set once the first key exchange has completed; a session never
+    /// rekeys before this.
+    pub finished: bool,
+    pub session_id: Option<Vec<u8>>,
+    /// Set as soon as either side's `SSH_MSG_KEXINIT` for the current
+    /// round has been sent/received, cleared once our incoming keys have
+    /// been activated on `SSH_MSG_NEWKEYS` (see `activate_incoming`).
+    /// Guards against treating a rekey's `SSH_MSG_KEXINIT` as an error and
+    /// against starting a second rekey while one is already in flight.
+    pub in_progress: bool,
+}
+
+impl Session {
+    pub(crate) fn kex(&self) -> &KexState {
+        &self.kex
+    }
+
+    /// RFC 4253 § 9 - either side may initiate a rekey at any time by
+    /// sending a fresh `SSH_MSG_KEXINIT`; called once the configured
+    /// byte/packet thresholds are crossed.
+    pub(crate) fn maybe_initiate_rekey(&mut self) -> Result<()> {
+        if !self.kex.finished || self.kex.in_progress || !self.codec().needs_rekey() {
+            return Ok(());
+        }
+
+        debug!("Rekey thresholds exceeded, initiating key re-exchange");
+        self.send_kexinit()?;
+        self.kex.in_progress = true;
+        Ok(())
+    }
+
+    // RFC 5656 § 4 (ECDH) / RFC 4253 § 8 (classic DH)
+    //
+    // The negotiated kex algorithm name decides which group-element math
+    // runs; everything past deriving `(public_value, shared_secret)` - the
+    // exchange hash, signature, reply packet and key derivation - is shared
+    // between them.
+    pub(crate) fn key_exchange(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let kex_algorithm = self
+            .algorithms
+            .as_ref()
+            .context("Cannot start key exchange before algorithm negotiation")?
+            .kex_algorithm
+            .clone();
+
+        match kex_algorithm.as_str() {
+            "curve25519-sha256" => self.key_exchange_ecdh(reader, MessageDigest::sha256()),
+            "diffie-hellman-group14-sha256" => self.key_exchange_dh(reader, MessageDigest::sha256()),
+            "diffie-hellman-group14-sha1" => self.key_exchange_dh(reader, MessageDigest::sha1()),
+            other => Err(anyhow!("Unsupported kex algorithm '{}'", other)),
+        }
+    }
+
+    fn key_exchange_ecdh(&mut self, reader: &mut PayloadReader, hash_algorithm: MessageDigest) -> Result<()> {
+        debug!("--- BEGIN KEY EXCHANGE (ECDH) ---");
+
+        let client_public_key = reader.next_string()?;
+
+        let server_ephemeral = PKey::generate_x25519()?;
+        let server_public_key = server_ephemeral.raw_public_key()?;
+
+        let client_pkey = PKey::public_key_from_raw_bytes(&client_public_key, Id::X25519)?;
+        let mut deriver = Deriver::new(&server_ephemeral)?;
+        deriver.set_peer(&client_pkey)?;
+        let shared_secret = deriver.derive_to_vec()?;
+
+        self.finish_key_exchange(
+            hash_algorithm,
+            &client_public_key,
+            &server_public_key,
+            &shared_secret,
+        )?;
+
+        debug!("--- END KEY EXCHANGE (ECDH) ---");
+        Ok(())
+    }
+
+    // RFC 4253 § 8 - diffie-hellman-group14-{sha1,sha256} run classic
+    // finite-field DH over the RFC 3526 § 3 2048-bit MODP group instead of
+    // an elliptic curve, but otherwise follow the same `SSH_MSG_KEXDH_INIT`
+    // / `SSH_MSG_KEXDH_REPLY` exchange (which reuse the ECDH message
+    // numbers - see the comment on `MessageType::SSH_MSG_KEX_ECDH_INIT`).
+    fn key_exchange_dh(&mut self, reader: &mut PayloadReader, hash_algorithm: MessageDigest) -> Result<()> {
+        debug!("--- BEGIN KEY EXCHANGE (DH group14) ---");
+
+        let client_public_value = reader.next_string()?;
+        let e = BigNum::from_slice(&client_public_value)?;
+
+        let p = group14_prime()?;
+        let g = BigNum::from_u32(2)?;
+        let mut ctx = BigNumContext::new()?;
+
+        // RFC 4253 § 8 - reject a client public value outside (1, p-1); 0, 1
+        // and p-1 each collapse the shared secret to a fixed,
+        // attacker-predictable value regardless of the server's exponent.
+        let one = BigNum::from_u32(1)?;
+        let mut p_minus_one = BigNum::new()?;
+        p_minus_one.checked_sub(&p, &one)?;
+        if e <= one || e >= p_minus_one {
+            return Err(anyhow!("Client DH public value 'e' is out of range"));
+        }
+
+        let mut y = BigNum::new()?;
+        p.rand_range(&mut y)?;
+
+        let mut f = BigNum::new()?;
+        f.mod_exp(&g, &y, &p, &mut ctx)?;
+
+        let mut k = BigNum::new()?;
+        k.mod_exp(&e, &y, &p, &mut ctx)?;
+
+        // RFC 4251 § 5 / RFC 4253 § 8 - `f` and `K` are `mpint`s, not plain
+        // byte strings: a leading 0x00 is required whenever the high bit of
+        // the first content byte is set, or a client reconstructing the
+        // exchange hash from the wire bytes would read a different (and
+        // negative-looking) value than the one the server just hashed.
+        let server_public_value = to_mpint(&f);
+        let shared_secret = to_mpint(&k);
+
+        self.finish_key_exchange(
+            hash_algorithm,
+            &client_public_value,
+            &server_public_value,
+            &shared_secret,
+        )?;
+
+        debug!("--- END KEY EXCHANGE (DH group14) ---");
+        Ok(())
+    }
+
+    /// Shared tail of both kex methods: hash the transcript, sign it with
+    /// the host key, reply, send `SSH_MSG_NEWKEYS` and derive the session
+    /// keys. `client_public_value`/`server_public_value` are `Q_C`/`Q_S` for
+    /// ECDH or `e`/`f` for classic DH.
+    fn finish_key_exchange(
+        &mut self,
+        hash_algorithm: MessageDigest,
+        client_public_value: &[u8],
+        server_public_value: &[u8],
+        shared_secret: &[u8],
+    ) -> Result<()> {
+        let host_key = &self.server_config.host_key;
+        let host_key_blob = encode_host_key_blob(host_key)?;
+
+        let exchange_hash = compute_exchange_hash(
+            hash_algorithm,
+            &self.client_ident,
+            &self.server_config.ident_string,
+            &self.client_kexinit_payload,
+            &self.server_kexinit_payload,
+            &host_key_blob,
+            client_public_value,
+            server_public_value,
+            shared_secret,
+        )?;
+
+        if self.kex.session_id.is_none() {
+            self.kex.session_id = Some(exchange_hash.clone());
+        }
+        let session_id = self.kex.session_id.clone().unwrap();
+
+        let signature = sign(host_key, &exchange_hash)?;
+
+        let packet = PacketBuilder::new(MessageType::SSH_MSG_KEX_ECDH_REPLY)
+            .write_bytes(&host_key_blob)
+            .write_bytes(server_public_value)
+            .write_bytes(&signature);
+        self.send_packet(packet)?;
+
+        let keys = derive_keys(hash_algorithm, shared_secret, &exchange_hash, &session_id)?;
+        let crypto = Crypto::new(MessageDigest::sha256(), MessageDigest::sha256());
+        let algorithms = self
+            .algorithms
+            .clone()
+            .context("Cannot finish key exchange before algorithm negotiation")?;
+        self.codec().stage_keys(&algorithms, &keys, &crypto)?;
+
+        // RFC 4253 § 9 - our outgoing traffic switches to the new keys right
+        // after we send our own SSH_MSG_NEWKEYS; the incoming side only
+        // switches once the peer's SSH_MSG_NEWKEYS is actually received (see
+        // the SSH_MSG_NEWKEYS handler in `handle_packet`), so in-flight
+        // traffic from the peer keeps decoding under the old keys.
+        self.send_packet(PacketBuilder::new(MessageType::SSH_MSG_NEWKEYS))?;
+        self.codec().activate_outgoing();
+
+        self.kex.finished = true;
+
+        Ok(())
+    }
+}
+
+// RFC 3526 § 3 - 2048-bit MODP Group 14, used by
+// diffie-hellman-group14-sha1/sha256. The generator is 2.
+const GROUP14_PRIME_HEX: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF",
+);
+
+fn group14_prime() -> Result<BigNum> {
+    BigNum::from_hex_str(GROUP14_PRIME_HEX).context("Failed parsing group14 prime")
+}
+
+// RFC 4251 § 5 - encodes a positive `BigNum` as the content bytes of an
+// `mpint`: big-endian, minimal length, with a leading 0x00 prepended
+// whenever the high bit of the first byte would otherwise be set.
+fn to_mpint(value: &BigNum) -> Vec<u8> {
+    let bytes = value.to_vec();
+    match bytes.first() {
+        Some(b) if b & 0x80 != 0 => {
+            let mut padded = Vec::with_capacity(bytes.len() + 1);
+            padded.push(0);
+            padded.extend(bytes);
+            padded
+        }
+        _ => bytes,
+    }
+}
+
+fn encode_host_key_blob(host_key: &PKey<Private>) -> Result<Vec<u8>> {
+    let raw = host_key.raw_public_key()?;
+    let mut blob = encode_string(b"ssh-ed25519");
+    blob.extend(encode_string(&raw));
+    Ok(blob)
+}
+
+fn sign(host_key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>> {
+    let mut signer = Signer::new_without_digest(host_key)?;
+    let signature = signer.sign_oneshot_to_vec(data)?;
+    let mut blob = encode_string(b"ssh-ed25519");
+    blob.extend(encode_string(&signature));
+    Ok(blob)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compute_exchange_hash(
+    hash_algorithm: MessageDigest,
+    client_ident: &str,
+    server_ident: &str,
+    client_kexinit: &[u8],
+    server_kexinit: &[u8],
+    host_key_blob: &[u8],
+    client_public_key: &[u8],
+    server_public_key: &[u8],
+    shared_secret: &[u8],
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.extend(encode_string(client_ident.as_bytes()));
+    buf.extend(encode_string(server_ident.as_bytes()));
+    buf.extend(encode_string(client_kexinit));
+    buf.extend(encode_string(server_kexinit));
+    buf.extend(encode_string(host_key_blob));
+    buf.extend(encode_string(client_public_key));
+    buf.extend(encode_string(server_public_key));
+    buf.extend(encode_string(shared_secret));
+
+    Ok(hash(hash_algorithm, &buf)
+        .context("Failed hashing key exchange data")?
+        .to_vec())
+}
+
+// RFC 4253 § 7.2
+fn derive_keys(
+    hash_algorithm: MessageDigest,
+    shared_secret: &[u8],
+    exchange_hash: &[u8],
+    session_id: &[u8],
+) -> Result<KeySet> {
+    let derive = |letter: u8, len: usize| -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.extend(encode_string(shared_secret));
+        buf.extend(exchange_hash);
+        buf.push(letter);
+        buf.extend(session_id);
+
+        let mut key = hash(hash_algorithm, &buf)
+            .context("Failed deriving key material")?
+            .to_vec();
+        while key.len() < len {
+            let mut extend_buf = Vec::new();
+            extend_buf.extend(encode_string(shared_secret));
+            extend_buf.extend(exchange_hash);
+            extend_buf.extend(&key);
+            key.extend(hash(hash_algorithm, &extend_buf)?.to_vec());
+        }
+        key.truncate(len);
+        Ok(key)
+    };
+
+    Ok(KeySet {
+        iv_client_server: derive(b'A', 16)?,
+        iv_server_client: derive(b'B', 16)?,
+        enc_key_client_server: derive(b'C', 16)?,
+        enc_key_server_client: derive(b'D', 16)?,
+        integrity_key_client_server: derive(b'E', 32)?,
+        integrity_key_server_client: derive(b'F', 32)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_mpint_prepends_zero_when_high_bit_set() {
+        let value = BigNum::from_slice(&[0x80, 0x01]).unwrap();
+        assert_eq!(to_mpint(&value), vec![0x00, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn to_mpint_leaves_value_unpadded_when_high_bit_clear() {
+        let value = BigNum::from_slice(&[0x7f, 0x01]).unwrap();
+        assert_eq!(to_mpint(&value), vec![0x7f, 0x01]);
+    }
+
+    #[test]
+    fn group14_prime_is_the_rfc_3526_2048_bit_group() {
+        let p = group14_prime().unwrap();
+        assert_eq!(p.num_bits(), 2048);
+    }
+
+    // Known-answer test: fixed transcript/kex fields hashed by hand with
+    // Python's hashlib to pin down `compute_exchange_hash`'s wire layout
+    // (eight length-prefixed `string`s per RFC 4251 § 5, in the order RFC
+    // 4253 § 8 specifies) and `derive_keys`'s RFC 4253 § 7.2 expansion.
+    #[test]
+    fn compute_exchange_hash_matches_known_answer() {
+        let exchange_hash = compute_exchange_hash(
+            MessageDigest::sha256(),
+            "SSH-2.0-client",
+            "SSH-2.0-server",
+            b"ckex",
+            b"skex",
+            b"hostkey",
+            b"e",
+            b"f",
+            b"K",
+        )
+        .unwrap();
+
+        assert_eq!(
+            exchange_hash,
+            vec![
+                0x0a, 0xb6, 0x9a, 0xd5, 0xd0, 0x97, 0x1b, 0x2c, 0x2a, 0xca, 0x0d, 0x06, 0xdd, 0xcd, 0x0f, 0x95,
+                0xd4, 0xff, 0x92, 0x67, 0x81, 0x5a, 0x5a, 0xf4, 0x3a, 0xad, 0xfb, 0x1a, 0x77, 0x9b, 0x17, 0xec,
+            ]
+        );
+
+        let keys = derive_keys(MessageDigest::sha256(), b"K", &exchange_hash, &exchange_hash).unwrap();
+        assert_eq!(
+            keys.iv_client_server,
+            vec![0x82, 0x41, 0x4a, 0xcf, 0xcb, 0x92, 0xc8, 0xfa, 0xbf, 0x7e, 0xf2, 0x47, 0xed, 0x35, 0x51, 0x11]
+        );
+        assert_eq!(
+            keys.iv_server_client,
+            vec![0x8b, 0xec, 0x68, 0x28, 0xe8, 0xf3, 0xe4, 0x76, 0x97, 0x3f, 0xcb, 0x5a, 0xec, 0xbc, 0x79, 0x5a]
+        );
+        assert_eq!(
+            keys.enc_key_client_server,
+            vec![0xba, 0xd7, 0xf9, 0xf5, 0xdc, 0x9e, 0xab, 0xea, 0x96, 0xd0, 0x90, 0xaa, 0x07, 0x6e, 0xb8, 0x19]
+        );
+        assert_eq!(
+            keys.enc_key_server_client,
+            vec![0x9d, 0xd8, 0xe9, 0xdc, 0xb0, 0xb6, 0x8d, 0x7f, 0xfe, 0xdb, 0xd6, 0x05, 0xcd, 0xcb, 0x7c, 0xcb]
+        );
+        assert_eq!(
+            keys.integrity_key_client_server,
+            vec![
+                0x2c, 0x84, 0x9d, 0xe3, 0xb9, 0xc1, 0x21, 0xa1, 0xcc, 0x8c, 0xf2, 0xfc, 0x4b, 0x1c, 0x30, 0x5e,
+                0xd3, 0x6c, 0x24, 0x3c, 0x2f, 0x0a, 0xa4, 0x99, 0x7c, 0x1c, 0x40, 0x94, 0x5c, 0x07, 0xd6, 0x9f,
+            ]
+        );
+        assert_eq!(
+            keys.integrity_key_server_client,
+            vec![
+                0x56, 0xf5, 0xe6, 0xae, 0x07, 0x24, 0xdd, 0xf5, 0xfa, 0xa7, 0x67, 0xd9, 0xc8, 0x05, 0x6a, 0xd8,
+                0x7a, 0x64, 0x00, 0x42, 0x37, 0x3c, 0xfb, 0x39, 0xba, 0x4f, 0x47, 0x82, 0x40, 0x0b, 0x4c, 0x73,
+            ]
+        );
+    }
+}
@@ -1,13 +1,19 @@
 use std::{
+    collections::HashMap,
     io::{BufRead, BufReader, Write},
     net::TcpStream,
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    thread,
 };
 
 use algorithm_negotiation::Algorithms;
 use anyhow::{anyhow, Context, Result};
+use key_exchange::KexState;
 use log::{debug, error, trace};
 
 use crate::{
+    channel::Channel,
+    codec::PacketCodec,
     decoding::{decode_packet, PayloadReader},
     encoding::PacketBuilder,
     types::{DisconnectReason, MessageType},
@@ -15,15 +21,40 @@ use crate::{
 };
 
 pub mod algorithm_negotiation;
+pub mod connection;
 pub mod key_exchange;
+pub mod user_auth;
 
 pub struct Session {
     stream: TcpStream,
-    outgoing_packet_sequence: u32,
-    incoming_packet_sequence: u32,
+    /// Shared with the background writer thread (and, once a shell channel
+    /// exists, with that channel's output-pump thread) so every packet -
+    /// whatever thread produces it - is sequenced and encrypted/MAC'd the
+    /// same way before it reaches the wire.
+    codec: Arc<Mutex<PacketCodec>>,
 
-    algorithms: Algorithms,
+    algorithms: Option<Algorithms>,
     server_config: ServerConfig,
+    kex: KexState,
+
+    /// Set once a `SSH_MSG_USERAUTH_SUCCESS` has been sent; gates everything
+    /// past the ssh-userauth service (RFC 4252 § 5.1).
+    authenticated: bool,
+
+    // For the connection protocol (RFC 4254), keyed by the channel number we
+    // assigned to each side.
+    channels: HashMap<u32, Channel>,
+    channel_peer_ids: HashMap<u32, u32>,
+    next_channel_id: u32,
+
+    /// Encoded packets ready to go out over `stream`; handed to the writer
+    /// thread spawned in `start`. Cloned into a `connection::ChannelSink`
+    /// for each shell channel so its output-pump thread can send
+    /// `SSH_MSG_CHANNEL_DATA` directly, without going through `&mut
+    /// Session`.
+    writer_tx: mpsc::Sender<Vec<u8>>,
+    /// Taken by `start` to spawn the writer thread; `None` afterwards.
+    writer_rx: Option<mpsc::Receiver<Vec<u8>>>,
 
     // For ECDH kex exchange
     client_ident: String,
@@ -33,13 +64,22 @@ pub struct Session {
 
 impl Session {
     pub fn new(stream: TcpStream, server_config: ServerConfig) -> Self {
+        let codec = PacketCodec::new(server_config.max_packet_length);
+        let (writer_tx, writer_rx) = mpsc::channel();
         Session {
             stream,
-            outgoing_packet_sequence: 0,
-            incoming_packet_sequence: 0,
+            codec: Arc::new(Mutex::new(codec)),
 
-            algorithms: Algorithms::default(),
+            algorithms: None,
             server_config,
+            kex: KexState::default(),
+            authenticated: false,
+
+            channels: HashMap::new(),
+            channel_peer_ids: HashMap::new(),
+            next_channel_id: 0,
+            writer_tx,
+            writer_rx: Some(writer_rx),
 
             client_ident: String::new(),
             client_kexinit_payload: Vec::new(),
@@ -47,6 +87,33 @@ impl Session {
         }
     }
 
+    pub(crate) fn stream_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    pub(crate) fn codec(&self) -> MutexGuard<'_, PacketCodec> {
+        self.codec.lock().unwrap()
+    }
+
+    /// Clones of these are what let a channel's output-pump thread
+    /// (`connection::ChannelSink`) send `SSH_MSG_CHANNEL_DATA` packets on
+    /// its own, through the same codec/writer as everything else.
+    pub(crate) fn codec_handle(&self) -> Arc<Mutex<PacketCodec>> {
+        Arc::clone(&self.codec)
+    }
+
+    pub(crate) fn writer_sender(&self) -> mpsc::Sender<Vec<u8>> {
+        self.writer_tx.clone()
+    }
+
+    pub(crate) fn algorithms(&self) -> &Option<Algorithms> {
+        &self.algorithms
+    }
+
+    pub(crate) fn server_config(&self) -> &ServerConfig {
+        &self.server_config
+    }
+
     /// This will handle all incoming packets, blocking this thread until disconnect.
     pub fn start(&mut self) -> Result<()> {
         debug!(
@@ -54,6 +121,30 @@ impl Session {
             self.stream.peer_addr().unwrap()
         );
 
+        // Prevents a stalled client from blocking this handler thread
+        // forever; a socket `read` then fails cleanly with `WouldBlock`/timeout
+        // instead of hanging.
+        self.stream
+            .set_read_timeout(Some(self.server_config.read_timeout))
+            .context("Failed to set read timeout on stream")?;
+
+        // `stream` itself is only ever read from now on; writing happens
+        // exclusively on this cloned handle, from this one dedicated writer
+        // thread, so a write from here can never race with the
+        // nonblocking-mode juggling a second writer would need.
+        let writer_rx = self.writer_rx.take().expect("writer thread already started");
+        let mut writer_stream = self
+            .stream
+            .try_clone()
+            .context("Failed to clone stream for writer thread")?;
+        thread::spawn(move || {
+            while let Ok(bytes) = writer_rx.recv() {
+                if writer_stream.write_all(&bytes).is_err() {
+                    break;
+                }
+            }
+        });
+
         self.client_ident = self
             .ident_exchange()
             .context("Failed during ident exchange")?;
@@ -67,6 +158,9 @@ impl Session {
                 }
                 break;
             }
+
+            self.maybe_initiate_rekey()
+                .context("Failed checking rekey thresholds")?;
         }
 
         Ok(())
@@ -75,14 +169,13 @@ impl Session {
     // RFC 4253 § 4.2
     fn ident_exchange(&mut self) -> Result<String> {
         debug!("--- BEGIN IDENTIFICATION EXCHANGE ---");
-        self.send_packet(format!("{}\r\n", self.server_config.ident_string).as_bytes())?;
+        self.send_raw(format!("{}\r\n", self.server_config.ident_string).as_bytes())?;
 
         let mut reader = BufReader::new(&mut self.stream);
         let mut client_ident = String::new();
-        self.incoming_packet_sequence += reader
+        reader
             .read_line(&mut client_ident)
-            .context("Failed reading client_ident")?
-            as u32;
+            .context("Failed reading client_ident")?;
         client_ident = client_ident.lines().next().unwrap().to_string();
         debug!("client = {:?}", client_ident);
 
@@ -119,14 +212,13 @@ impl Session {
 
     // TODO: Handle packets like `ssh_dispatch_set` from openssh
     fn handle_packet(&mut self) -> Result<Option<DisconnectReason>> {
-        let packet = decode_packet(&self.stream)?;
-        self.incoming_packet_sequence += packet.entire_packet_length();
+        let packet = decode_packet(self)?;
 
         let msg_type = packet.message_type()?;
         trace!(
             "Received message of type = {:?}, current packet sequence = {}",
             msg_type,
-            self.incoming_packet_sequence
+            self.codec().incoming_sequence()
         );
 
         let mut reader = PayloadReader::new(packet.payload());
@@ -149,6 +241,68 @@ impl Session {
                     .context("Failed during handling SSH_MSG_KEX_ECDH_INIT")?;
             }
 
+            // RFC 4253 § 9 - the peer's own keys take effect on their side
+            // right after they send this, so our incoming decryption only
+            // switches over now rather than when we sent ours.
+            MessageType::SSH_MSG_NEWKEYS => {
+                self.codec().activate_incoming();
+                self.kex.in_progress = false;
+            }
+
+            MessageType::SSH_MSG_SERVICE_REQUEST => {
+                self.service_request(&mut reader)
+                    .context("Failed during handling SSH_MSG_SERVICE_REQUEST")?;
+            }
+
+            MessageType::SSH_MSG_USERAUTH_REQUEST => {
+                self.userauth_request(&packet, &mut reader)
+                    .context("Failed during handling SSH_MSG_USERAUTH_REQUEST")?;
+            }
+
+            // RFC 4254 § 1 - the connection protocol only runs once
+            // ssh-userauth has succeeded (RFC 4252 § 5.1); a client that
+            // skips straight to it gets treated like any other unhandled
+            // message instead of being allowed to open a shell.
+            MessageType::SSH_MSG_CHANNEL_OPEN
+            | MessageType::SSH_MSG_CHANNEL_REQUEST
+            | MessageType::SSH_MSG_CHANNEL_DATA
+            | MessageType::SSH_MSG_CHANNEL_WINDOW_ADJUST
+            | MessageType::SSH_MSG_CHANNEL_EOF
+            | MessageType::SSH_MSG_CHANNEL_CLOSE
+                if !self.authenticated =>
+            {
+                error!(
+                    "Rejecting {:?} before authentication has completed",
+                    msg_type
+                );
+                self.reply_unimplemented()?;
+            }
+
+            MessageType::SSH_MSG_CHANNEL_OPEN => {
+                self.channel_open(&mut reader)
+                    .context("Failed during handling SSH_MSG_CHANNEL_OPEN")?;
+            }
+            MessageType::SSH_MSG_CHANNEL_REQUEST => {
+                self.channel_request(&mut reader)
+                    .context("Failed during handling SSH_MSG_CHANNEL_REQUEST")?;
+            }
+            MessageType::SSH_MSG_CHANNEL_DATA => {
+                self.channel_data(&mut reader)
+                    .context("Failed during handling SSH_MSG_CHANNEL_DATA")?;
+            }
+            MessageType::SSH_MSG_CHANNEL_WINDOW_ADJUST => {
+                self.channel_window_adjust(&mut reader)
+                    .context("Failed during handling SSH_MSG_CHANNEL_WINDOW_ADJUST")?;
+            }
+            MessageType::SSH_MSG_CHANNEL_EOF => {
+                self.channel_eof(&mut reader)
+                    .context("Failed during handling SSH_MSG_CHANNEL_EOF")?;
+            }
+            MessageType::SSH_MSG_CHANNEL_CLOSE => {
+                self.channel_close(&mut reader)
+                    .context("Failed during handling SSH_MSG_CHANNEL_CLOSE")?;
+            }
+
             _ => {
                 error!(
                     "Unhandled message type.\ntype: {:?}\npayload: {:?}",
@@ -156,33 +310,47 @@ impl Session {
                     String::from_utf8_lossy(&packet.payload())
                 );
 
-                let packet = PacketBuilder::new(MessageType::SSH_MSG_UNIMPLEMENTED)
-                    .write_u32(self.incoming_packet_sequence)
-                    .build()?;
-                self.send_packet(&packet)?;
+                self.reply_unimplemented()?;
             }
         }
 
         Ok(None)
     }
 
-    fn send_packet(&mut self, packet: &[u8]) -> Result<()> {
-        self.outgoing_packet_sequence += packet.len() as u32;
-        self.stream
-            .write_all(packet)
-            .context("Failed sending packet")?;
+    // RFC 4253 § 11.4
+    fn reply_unimplemented(&mut self) -> Result<()> {
+        let packet = PacketBuilder::new(MessageType::SSH_MSG_UNIMPLEMENTED)
+            .write_u32(self.codec().incoming_sequence());
+        self.send_packet(packet)
+    }
 
-        Ok(())
+    /// Encodes `packet` through the codec (applying encryption/MAC once a
+    /// key exchange has completed) and hands the result to the writer
+    /// thread spawned in `start`, which is the only thing that ever writes
+    /// to `stream`.
+    fn send_packet(&mut self, packet: PacketBuilder) -> Result<()> {
+        let encoded = self.codec().encode(packet)?;
+        self.writer_tx
+            .send(encoded)
+            .map_err(|_| anyhow!("Writer thread has shut down"))
+    }
+
+    /// Hands raw bytes that bypass packet framing entirely to the writer
+    /// thread - only used for the plaintext identification string (RFC
+    /// 4253 § 4.2).
+    fn send_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.writer_tx
+            .send(data.to_vec())
+            .map_err(|_| anyhow!("Writer thread has shut down"))
     }
 
     // RFC 4253 § 11.1
-    fn disconnect(&mut self, reason: DisconnectReason) -> Result<()> {
+    pub(crate) fn disconnect(&mut self, reason: DisconnectReason) -> Result<()> {
         let packet = PacketBuilder::new(MessageType::SSH_MSG_DISCONNECT)
             .write_byte(reason.clone() as u8)
             .write_bytes(b"")
-            .write_bytes(b"en")
-            .build()?;
-        self.send_packet(&packet)?;
+            .write_bytes(b"en");
+        self.send_packet(packet)?;
 
         debug!("Disconnecting because of {:?}", &reason);
         Ok(())
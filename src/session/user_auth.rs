@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+
+use crate::{
+    crypto::verify_signature,
+    decoding::{u8_to_bool, DecodedPacket, PayloadReader},
+    encoding::{encode_string, PacketBuilder},
+    session::Session,
+    types::MessageType,
+};
+
+const USERAUTH_SERVICE_NAME: &str = "ssh-userauth";
+const AUTH_METHODS: &[&str] = &["publickey", "password"];
+
+impl Session {
+    // RFC 4253 § 10
+    pub(crate) fn service_request(&mut self, reader: &mut PayloadReader) -> Result<()> {
+        let service_name = String::from_utf8(reader.next_string()?)?;
+        debug!("Client requested service '{}'", service_name);
+
+        if service_name != USERAUTH_SERVICE_NAME {
+            return Err(anyhow!("Unsupported service '{}'", service_name));
+        }
+
+        let packet = PacketBuilder::new(MessageType::SSH_MSG_SERVICE_ACCEPT)
+            .write_bytes(service_name.as_bytes());
+        self.send_packet(packet)
+    }
+
+    // RFC 4252 § 5
+    pub(crate) fn userauth_request(
+        &mut self,
+        packet: &DecodedPacket,
+        reader: &mut PayloadReader,
+    ) -> Result<()> {
+        let user_name = String::from_utf8(reader.next_string()?)?;
+        let service_name = String::from_utf8(reader.next_string()?)?;
+        let method_name = String::from_utf8(reader.next_string()?)?;
+        debug!(
+            "SSH_MSG_USERAUTH_REQUEST user = {:?}, method = {:?}",
+            user_name, method_name
+        );
+
+        let authenticated = match method_name.as_str() {
+            "password" => self.userauth_password(&user_name, reader)?,
+            "publickey" => self.userauth_publickey(packet, &user_name, &service_name, reader)?,
+            _ => false,
+        };
+
+        if authenticated {
+            self.authenticated = true;
+            self.send_packet(PacketBuilder::new(MessageType::SSH_MSG_USERAUTH_SUCCESS))?;
+        } else {
+            self.send_auth_failure()?;
+        }
+
+        Ok(())
+    }
+
+    fn send_auth_failure(&mut self) -> Result<()> {
+        let packet = PacketBuilder::new(MessageType::SSH_MSG_USERAUTH_FAILURE)
+            .write_name_list(AUTH_METHODS)
+            .write_bool(false);
+        self.send_packet(packet)
+    }
+
+    fn find_user(&self, user_name: &str) -> Option<&crate::AuthorizedUser> {
+        self.server_config
+            .authorized_users
+            .iter()
+            .find(|user| user.username == user_name)
+    }
+
+    fn userauth_password(&mut self, user_name: &str, reader: &mut PayloadReader) -> Result<bool> {
+        let _change_password = u8_to_bool(reader.next_byte().ok_or_else(|| anyhow!("Packet too short - 'change_password' could not be read"))?)?;
+        let password = String::from_utf8(reader.next_string()?)?;
+
+        let matches = self
+            .find_user(user_name)
+            .and_then(|user| user.password.as_ref())
+            .is_some_and(|expected| expected == &password);
+
+        if !matches {
+            warn!("Failed password authentication for user '{}'", user_name);
+        }
+        Ok(matches)
+    }
+
+    fn userauth_publickey(
+        &mut self,
+        packet: &DecodedPacket,
+        user_name: &str,
+        service_name: &str,
+        reader: &mut PayloadReader,
+    ) -> Result<bool> {
+        let has_signature = u8_to_bool(
+            reader
+                .next_byte()
+                .ok_or_else(|| anyhow!("Packet too short - 'has_signature' could not be read"))?,
+        )?;
+        let public_key_algorithm = String::from_utf8(reader.next_string()?)?;
+        let public_key_blob = reader.next_string()?;
+
+        let Some(user) = self.find_user(user_name) else {
+            return Ok(false);
+        };
+        let key_allowed = user
+            .authorized_keys
+            .iter()
+            .any(|key| key.algorithm == public_key_algorithm && key.blob == public_key_blob);
+        if !key_allowed {
+            warn!("Public key rejected for user '{}' - not in authorized_keys", user_name);
+            return Ok(false);
+        }
+
+        if !has_signature {
+            // RFC 4252 § 7 - client is only probing whether the key would be
+            // acceptable before computing a signature.
+            let response = PacketBuilder::new(MessageType::SSH_MSG_USERAUTH_PK_OK)
+                .write_bytes(public_key_algorithm.as_bytes())
+                .write_bytes(&public_key_blob);
+            self.send_packet(response)?;
+            return Ok(false);
+        }
+
+        let signature_blob = reader.next_string()?;
+
+        let session_id = self
+            .kex()
+            .session_id
+            .clone()
+            .ok_or_else(|| anyhow!("Cannot verify publickey signature before key exchange"))?;
+
+        let mut signed_data = encode_string(&session_id);
+        signed_data.push(MessageType::SSH_MSG_USERAUTH_REQUEST as u8);
+        signed_data.extend(encode_string(user_name.as_bytes()));
+        signed_data.extend(encode_string(service_name.as_bytes()));
+        signed_data.extend(encode_string(b"publickey"));
+        signed_data.push(1);
+        signed_data.extend(encode_string(public_key_algorithm.as_bytes()));
+        signed_data.extend(encode_string(&public_key_blob));
+        let _ = packet;
+
+        verify_signature(
+            &public_key_algorithm,
+            &public_key_blob,
+            &signature_blob,
+            &signed_data,
+        )
+    }
+}